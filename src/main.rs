@@ -1,24 +1,66 @@
-use calamine::{open_workbook, Reader, Xlsx};
+use bitflags::bitflags;
+use calamine::{open_workbook_auto, Reader, Sheets};
 use clap::Parser;
 use dialoguer::console::Term;
 use dialoguer::{theme::ColorfulTheme, Select};
 use encoding_rs::WINDOWS_1252;
 use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, IsTerminal, Read, Seek, Write};
 use std::ops::RangeInclusive;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, help = "Path to detailed permission report text file", value_hint=clap::ValueHint::FilePath)]
-    license: String,
-    #[arg(short, long, help = "Path to exported objects in xlsx format", value_hint=clap::ValueHint::FilePath)]
-    objects: String,
+    #[arg(short, long, help = "Path to a detailed permission report text file, repeatable (defaults to only the built-in 50000 range)", value_hint=clap::ValueHint::FilePath)]
+    license: Vec<String>,
+    #[arg(short, long, help = "Path to exported objects (xlsx, xlsm, xlsb, xls, or ods), repeatable", value_hint=clap::ValueHint::FilePath)]
+    objects: Vec<String>,
+    #[arg(
+        long,
+        help = "Sheet to read from the objects file (skips the interactive prompt)"
+    )]
+    sheet: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Number of header rows to skip before the object data starts"
+    )]
+    header_row: usize,
+    #[arg(
+        long,
+        help = "Minimum permission letters required on every checked object (default: X, or RIMDX for TableData)"
+    )]
+    require: Option<String>,
+    #[arg(
+        long,
+        help = "Path to write the report to (defaults to missing-permissions.<format>)"
+    )]
+    output: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv, help = "Report file format")]
+    output_format: OutputFormat,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Csv,
+    Ods,
+    Xlsx,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Ods => "ods",
+            OutputFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum ObjectType {
     TableData,
     Table,
@@ -67,7 +109,7 @@ impl ObjectType {
         }
     }
 
-    pub fn to_string(self: &Self) -> &str {
+    pub fn to_string(&self) -> &str {
         match self {
             ObjectType::TableData => "TableData",
             ObjectType::Table => "Table",
@@ -92,7 +134,7 @@ impl ObjectType {
         }
     }
 
-    pub fn is_licensed(self: &Self) -> bool {
+    pub fn is_licensed(&self) -> bool {
         match self {
             ObjectType::TableData
             | ObjectType::Report
@@ -118,13 +160,64 @@ impl ObjectType {
     }
 }
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Permission: u8 {
+        const READ = 0b00001;
+        const INSERT = 0b00010;
+        const MODIFY = 0b00100;
+        const DELETE = 0b01000;
+        const EXECUTE = 0b10000;
+    }
+}
+
+impl Permission {
+    pub fn from_str(permission: &str) -> Self {
+        permission.chars().fold(Self::empty(), |acc, letter| {
+            acc | match letter {
+                'R' => Self::READ,
+                'I' => Self::INSERT,
+                'M' => Self::MODIFY,
+                'D' => Self::DELETE,
+                'X' => Self::EXECUTE,
+                '-' => Self::empty(),
+                _ => {
+                    eprintln!("Warning: ignoring unknown permission letter '{letter}'");
+                    Self::empty()
+                }
+            }
+        })
+    }
+
+    pub fn to_letters(self) -> String {
+        [
+            (Self::READ, 'R'),
+            (Self::INSERT, 'I'),
+            (Self::MODIFY, 'M'),
+            (Self::DELETE, 'D'),
+            (Self::EXECUTE, 'X'),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.contains(*flag))
+        .map(|(_, letter)| letter)
+        .collect()
+    }
+
+    pub fn default_required(object_type: &ObjectType) -> Self {
+        match object_type {
+            ObjectType::TableData => Self::from_str("RIMDX"),
+            _ => Self::EXECUTE,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ObjectRange {
     object_type: ObjectType,
     quantity: i64,
     range_from: i64,
     range_to: i64,
-    permission: String,
+    permission: Permission,
 }
 
 impl ObjectRange {
@@ -134,12 +227,41 @@ impl ObjectRange {
             quantity: range_to - range_from + 1,
             range_from,
             range_to,
-            permission: permission.to_owned(),
+            permission: Permission::from_str(permission),
         }
     }
 }
 
-#[derive(Debug)]
+/// Merges overlapping or adjacent ranges of the same object type into disjoint ranges so
+/// that capacity (quantity) and usage are computed over the same set of covered IDs, even
+/// when a license file restates a range the built-in seeds (or another license file) already
+/// cover.
+fn merge_overlapping_ranges(mut ranges: Vec<ObjectRange>) -> Vec<ObjectRange> {
+    ranges.sort_by(|a, b| {
+        a.object_type
+            .to_string()
+            .cmp(b.object_type.to_string())
+            .then(a.range_from.cmp(&b.range_from))
+    });
+
+    let mut merged: Vec<ObjectRange> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last)
+                if last.object_type == range.object_type && range.range_from <= last.range_to + 1 =>
+            {
+                last.range_to = last.range_to.max(range.range_to);
+                last.quantity = last.range_to - last.range_from + 1;
+                last.permission |= range.permission;
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+#[derive(Debug, Clone)]
 struct Object {
     object_type: ObjectType,
     id: i64,
@@ -156,6 +278,13 @@ impl Object {
     }
 }
 
+#[derive(Debug)]
+struct DeficientObject {
+    object: Object,
+    effective: Permission,
+    required: Permission,
+}
+
 fn read_file(
     path: &String,
     encoding: &'static encoding_rs::Encoding,
@@ -169,26 +298,35 @@ fn read_file(
     let mut result = String::new();
     reader.read_to_string(&mut result)?;
 
-    return Ok(result);
+    Ok(result)
 }
 
-fn pick_sheet<RS: Read + Seek>(excel: &Xlsx<RS>) -> Result<String, &str> {
+fn pick_sheet<RS: Read + Seek>(excel: &Sheets<RS>, sheet: Option<&str>) -> Result<String, String> {
     let sheet_names = excel.sheet_names();
 
+    if let Some(name) = sheet {
+        return sheet_names
+            .iter()
+            .find(|s| s.as_str() == name)
+            .cloned()
+            .ok_or_else(|| format!("No sheet named '{name}'"));
+    }
+
     match sheet_names.len() {
-        0 => return Err("No sheets"),
-        1 => return Ok(sheet_names.first().unwrap().clone()),
+        0 => Err("No sheets".to_owned()),
+        1 => Ok(sheet_names.first().unwrap().clone()),
+        _ if !io::stdin().is_terminal() => Ok(sheet_names.first().unwrap().clone()),
         _ => {
             let selection = Select::with_theme(&ColorfulTheme::default())
-                .items(sheet_names)
+                .items(&sheet_names)
                 .default(0)
                 .interact_on_opt(&Term::stderr())
-                .or(Err("Terminal error"))?;
+                .or(Err("Terminal error".to_owned()))?;
 
-            return match selection {
+            match selection {
                 Some(index) => Ok(sheet_names[index].clone()),
-                None => Err("Select a sheet!"),
-            };
+                None => Err("Select a sheet!".to_owned()),
+            }
         }
     }
 }
@@ -209,111 +347,237 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut objects: Vec<Object> = Vec::new();
 
-    let license_file =
-        read_file(&args.license, WINDOWS_1252).expect("Could not read the license info file!");
+    for license in &args.license {
+        let license_file =
+            read_file(license, WINDOWS_1252).expect("Could not read the license info file!");
 
-    let skip = license_file
-        .lines()
-        .into_iter()
-        .skip_while(|p| *p != "Object Assignment");
+        let skip = license_file
+            .lines()
+            .skip_while(|p| *p != "Object Assignment");
 
-    for line in skip
-        .skip(5)
-        .take_while(|p| *p != "Module Objects and Permissions")
-        .filter(|p| *p != "")
-    {
-        let words = line.split_whitespace();
-        if let &[object_type, _, range_from, range_to, permission] =
-            words.collect::<Vec<&str>>().as_slice()
+        for line in skip
+            .skip(5)
+            .take_while(|p| *p != "Module Objects and Permissions")
+            .filter(|p| !p.is_empty())
         {
-            licensed_object_ranges.push(ObjectRange::new(
-                object_type,
-                range_from.parse::<i64>()?,
-                range_to.parse::<i64>()?,
-                permission,
-            ));
-        } else {
-            unimplemented!("Unimplemented license format.");
+            let words = line.split_whitespace();
+            if let &[object_type, _, range_from, range_to, permission] =
+                words.collect::<Vec<&str>>().as_slice()
+            {
+                licensed_object_ranges.push(ObjectRange::new(
+                    object_type,
+                    range_from.parse::<i64>()?,
+                    range_to.parse::<i64>()?,
+                    permission,
+                ));
+            } else {
+                unimplemented!("Unimplemented license format.");
+            }
         }
     }
 
-    let mut excel: Xlsx<_> = open_workbook(args.objects).expect("Could not read the objects file!");
-    let selected_sheet = pick_sheet(&excel)?;
-
-    if let Some(Ok(r)) = excel.worksheet_range(&selected_sheet) {
-        for row in r.rows().skip(1) {
-            if let [object_type, object_id, name, ..] = row {
-                objects.push(Object::new(
-                    &object_type.to_string(),
-                    if object_id.is_int() {
-                        object_id.get_int().unwrap()
-                    } else if object_id.is_float() {
-                        object_id.get_float().unwrap() as i64
-                    } else {
-                        unimplemented!("Object id is not a number {}!", object_id.to_string());
-                    },
-                    &name.to_string(),
-                ));
-            } else {
-                unimplemented!("Unimplemented row format.");
+    for objects_path in &args.objects {
+        let mut excel: Sheets<_> =
+            open_workbook_auto(objects_path).expect("Could not read the objects file!");
+        let selected_sheet = pick_sheet(&excel, args.sheet.as_deref())?;
+
+        if let Some(Ok(r)) = excel.worksheet_range(&selected_sheet) {
+            for row in r.rows().skip(args.header_row) {
+                if let [object_type, object_id, name, ..] = row {
+                    let object = Object::new(
+                        &object_type.to_string(),
+                        if object_id.is_int() {
+                            object_id.get_int().unwrap()
+                        } else if object_id.is_float() {
+                            object_id.get_float().unwrap() as i64
+                        } else {
+                            unimplemented!("Object id is not a number {}!", object_id.to_string());
+                        },
+                        &name.to_string(),
+                    );
+
+                    match objects
+                        .iter()
+                        .find(|e| e.object_type == object.object_type && e.id == object.id)
+                    {
+                        Some(existing) if existing.name != object.name => {
+                            eprintln!(
+                                "Warning: {} {} was exported with conflicting names '{}' and '{}'; keeping '{}'",
+                                object.object_type.to_string(),
+                                object.id,
+                                existing.name,
+                                object.name,
+                                existing.name
+                            );
+                        }
+                        Some(_) => {}
+                        None => objects.push(object),
+                    }
+                } else {
+                    unimplemented!("Unimplemented row format.");
+                }
             }
         }
     }
 
-    let mut missing_objects: Vec<Object> = Vec::new();
+    let licensed_object_ranges = merge_overlapping_ranges(licensed_object_ranges);
+
+    let global_require = args.require.as_deref().map(Permission::from_str);
+
+    let mut range_usage: Vec<i64> = vec![0; licensed_object_ranges.len()];
+    let mut deficient_objects: Vec<DeficientObject> = Vec::new();
 
     for object in objects
-        .into_iter()
+        .iter()
         .filter(|e| e.object_type.is_licensed())
         .filter(|e| checked_range.contains(&e.id))
     {
-        let found_index = licensed_object_ranges.iter().position(|e| {
-            e.object_type == object.object_type && (e.range_from..=e.range_to).contains(&object.id)
-        });
-
-        match found_index {
-            Some(_) => {}
-            None => {
-                missing_objects.push(object);
+        let mut effective = Permission::empty();
+        let mut counted = false;
+
+        for (index, range) in licensed_object_ranges.iter().enumerate() {
+            if range.object_type == object.object_type
+                && (range.range_from..=range.range_to).contains(&object.id)
+            {
+                effective |= range.permission;
+
+                // Attribute the object to a single overlapping range so license files that
+                // restate the built-in ranges don't double-count it against capacity.
+                if !counted {
+                    range_usage[index] += 1;
+                    counted = true;
+                }
             }
         }
+
+        let required =
+            global_require.unwrap_or_else(|| Permission::default_required(&object.object_type));
+
+        if !effective.contains(required) {
+            deficient_objects.push(DeficientObject {
+                object: object.clone(),
+                effective,
+                required,
+            });
+        }
     }
 
     // TODO print stats - how many objects found?
 
-    if missing_objects.is_empty() {
-        println!("No missing objects found!");
+    for deficient in &deficient_objects {
+        let object = &deficient.object;
+        let missing = deficient.required.difference(deficient.effective);
+
+        println!(
+            "{} {}\t{}\tmissing: {}",
+            object.id,
+            object.object_type.to_string(),
+            object.name,
+            missing.to_letters()
+        );
+    }
+
+    print_coverage_report(&licensed_object_ranges, &range_usage);
+
+    if deficient_objects.is_empty() {
+        println!("No missing or under-permissioned objects found!");
         return Ok(());
     }
 
-    let path = "missing-permissions.csv";
+    let path = args
+        .output
+        .unwrap_or_else(|| format!("missing-permissions.{}", args.output_format.extension()));
+
+    match args.output_format {
+        OutputFormat::Csv => write_csv_report(&path, &deficient_objects)?,
+        OutputFormat::Ods => write_ods_report(
+            &path,
+            &deficient_objects,
+            &licensed_object_ranges,
+            &range_usage,
+        )?,
+        OutputFormat::Xlsx => write_xlsx_report(
+            &path,
+            &deficient_objects,
+            &licensed_object_ranges,
+            &range_usage,
+        )?,
+    }
 
-    let file = fs::File::create(&path)?;
-    let mut file = io::LineWriter::new(file);
+    println!("Wrote missing permissions to {path}");
 
-    file.write_all(b"ObjectType,FromObjectID,ToObjectID,Read,Insert,Modify,Delete,Execute,AvailableRange,Used,ObjectTypeRemaining,CompanyObjectPermissionID\n")?;
+    Ok(())
+}
 
-    for object in &missing_objects {
+fn print_coverage_report(licensed_object_ranges: &[ObjectRange], range_usage: &[i64]) {
+    let mut totals: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+
+    for (range, used) in licensed_object_ranges.iter().zip(range_usage) {
+        let totals = totals
+            .entry(range.object_type.to_string())
+            .or_insert((0, 0));
+        totals.0 += range.quantity;
+        totals.1 += used;
+    }
+
+    println!();
+    println!("License coverage:");
+    for (object_type, (quantity, used)) in &totals {
         println!(
-            "{} {}\t{}",
-            object.id,
-            object.object_type.to_string(),
-            object.name
+            "{object_type}: {used} of {quantity} slots used, {} free",
+            quantity - used
+        );
+    }
+
+    let unused_ranges: Vec<&ObjectRange> = licensed_object_ranges
+        .iter()
+        .zip(range_usage)
+        .filter(|(_, used)| **used == 0)
+        .map(|(range, _)| range)
+        .collect();
+
+    if unused_ranges.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("Purchased but unused license ranges:");
+    for range in unused_ranges {
+        println!(
+            "{} {}-{} ({})",
+            range.object_type.to_string(),
+            range.range_from,
+            range.range_to,
+            range.permission.to_letters()
         );
+    }
+}
+
+fn write_csv_report(
+    path: &str,
+    deficient_objects: &[DeficientObject],
+) -> Result<(), Box<dyn Error>> {
+    let file = fs::File::create(path)?;
+    let mut file = io::LineWriter::new(file);
+
+    file.write_all(b"ObjectType,FromObjectID,ToObjectID,Read,Insert,Modify,Delete,Execute,AvailableRange,Used,ObjectTypeRemaining,CompanyObjectPermissionID\n")?;
 
+    for deficient in deficient_objects {
+        let object = &deficient.object;
         let object_id = object.id.to_string();
-        let quantity = (object.id - object.id + 1).to_string();
+        let missing = deficient.required.difference(deficient.effective);
+        let grant = |permission| if missing.contains(permission) { "Direct" } else { "" };
         let line = vec![
             object.object_type.to_string(),
             &object_id,
             &object_id,
-            "Direct",
-            "Direct",
-            "Direct",
-            "Direct",
-            "Direct",
+            grant(Permission::READ),
+            grant(Permission::INSERT),
+            grant(Permission::MODIFY),
+            grant(Permission::DELETE),
+            grant(Permission::EXECUTE),
             "50000 - 99999",
-            &quantity,
+            "1",
             "0",
             "0",
         ];
@@ -323,11 +587,142 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     file.flush()?;
 
-    println!("Wrote missing permissions to {path}");
+    Ok(())
+}
+
+fn write_xlsx_report(
+    path: &str,
+    deficient_objects: &[DeficientObject],
+    licensed_object_ranges: &[ObjectRange],
+    range_usage: &[i64],
+) -> Result<(), Box<dyn Error>> {
+    let mut workbook = rust_xlsxwriter::Workbook::new();
+    let header_format = rust_xlsxwriter::Format::new().set_bold();
+
+    let missing_sheet = workbook.add_worksheet().set_name("Missing objects")?;
+    let headers = [
+        "ObjectType",
+        "ObjectID",
+        "ObjectName",
+        "Effective",
+        "Required",
+        "Missing",
+    ];
+    for (column, header) in headers.iter().enumerate() {
+        missing_sheet.write_with_format(0, column as u16, *header, &header_format)?;
+    }
+    for (row, deficient) in deficient_objects.iter().enumerate() {
+        let object = &deficient.object;
+        let row = row as u32 + 1;
+        missing_sheet.write(row, 0, object.object_type.to_string())?;
+        missing_sheet.write(row, 1, object.id)?;
+        missing_sheet.write(row, 2, &object.name)?;
+        missing_sheet.write(row, 3, deficient.effective.to_letters())?;
+        missing_sheet.write(row, 4, deficient.required.to_letters())?;
+        missing_sheet.write(
+            row,
+            5,
+            deficient
+                .required
+                .difference(deficient.effective)
+                .to_letters(),
+        )?;
+    }
 
-    // TODO print objects that are not needed?
+    let summary_sheet = workbook.add_worksheet().set_name("License summary")?;
+    let summary_headers = [
+        "ObjectType",
+        "FromObjectID",
+        "ToObjectID",
+        "Permission",
+        "Quantity",
+        "Used",
+        "Remaining",
+    ];
+    for (column, header) in summary_headers.iter().enumerate() {
+        summary_sheet.write_with_format(0, column as u16, *header, &header_format)?;
+    }
+    for (row, (range, used)) in licensed_object_ranges.iter().zip(range_usage).enumerate() {
+        let row = row as u32 + 1;
+        summary_sheet.write(row, 0, range.object_type.to_string())?;
+        summary_sheet.write(row, 1, range.range_from)?;
+        summary_sheet.write(row, 2, range.range_to)?;
+        summary_sheet.write(row, 3, range.permission.to_letters())?;
+        summary_sheet.write(row, 4, range.quantity)?;
+        summary_sheet.write(row, 5, *used)?;
+        summary_sheet.write(row, 6, range.quantity - used)?;
+    }
+
+    workbook.save(path)?;
+
+    Ok(())
+}
+
+fn write_ods_report(
+    path: &str,
+    deficient_objects: &[DeficientObject],
+    licensed_object_ranges: &[ObjectRange],
+    range_usage: &[i64],
+) -> Result<(), Box<dyn Error>> {
+    let mut workbook = spreadsheet_ods::WorkBook::new_empty();
+
+    let mut missing_sheet = spreadsheet_ods::Sheet::new("Missing objects");
+    let headers = [
+        "ObjectType",
+        "ObjectID",
+        "ObjectName",
+        "Effective",
+        "Required",
+        "Missing",
+    ];
+    for (column, header) in headers.iter().enumerate() {
+        missing_sheet.set_value(0, column as u32, *header);
+    }
+    for (row, deficient) in deficient_objects.iter().enumerate() {
+        let object = &deficient.object;
+        let row = row as u32 + 1;
+        missing_sheet.set_value(row, 0, object.object_type.to_string());
+        missing_sheet.set_value(row, 1, object.id);
+        missing_sheet.set_value(row, 2, object.name.as_str());
+        missing_sheet.set_value(row, 3, deficient.effective.to_letters());
+        missing_sheet.set_value(row, 4, deficient.required.to_letters());
+        missing_sheet.set_value(
+            row,
+            5,
+            deficient
+                .required
+                .difference(deficient.effective)
+                .to_letters(),
+        );
+    }
+    workbook.push_sheet(missing_sheet);
+
+    let mut summary_sheet = spreadsheet_ods::Sheet::new("License summary");
+    let summary_headers = [
+        "ObjectType",
+        "FromObjectID",
+        "ToObjectID",
+        "Permission",
+        "Quantity",
+        "Used",
+        "Remaining",
+    ];
+    for (column, header) in summary_headers.iter().enumerate() {
+        summary_sheet.set_value(0, column as u32, *header);
+    }
+    for (row, (range, used)) in licensed_object_ranges.iter().zip(range_usage).enumerate() {
+        let row = row as u32 + 1;
+        summary_sheet.set_value(row, 0, range.object_type.to_string());
+        summary_sheet.set_value(row, 1, range.range_from);
+        summary_sheet.set_value(row, 2, range.range_to);
+        summary_sheet.set_value(row, 3, range.permission.to_letters());
+        summary_sheet.set_value(row, 4, range.quantity);
+        summary_sheet.set_value(row, 5, *used);
+        summary_sheet.set_value(row, 6, range.quantity - used);
+    }
+    workbook.push_sheet(summary_sheet);
 
-    // TODO make permission file input optional?
+    spreadsheet_ods::write_ods(&mut workbook, path)?;
 
     Ok(())
 }